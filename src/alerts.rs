@@ -0,0 +1,52 @@
+use crate::state::StateStore;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tracks the last time each alert key fired so repeated checks against an
+/// unresolved condition don't re-notify on every run. Persisted as JSON
+/// through a `StateStore` (a local file, or S3 with `--state-bucket`).
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AlertHistory {
+    last_fired: HashMap<String, i64>,
+}
+
+impl AlertHistory {
+    pub async fn load(store: &dyn StateStore) -> Self {
+        match store.read().await {
+            Ok(Some(contents)) => serde_json::from_str(&contents).unwrap_or_default(),
+            _ => Self::default(),
+        }
+    }
+
+    pub async fn save(&self, store: &dyn StateStore) -> Result<()> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        store.write(&serialized).await
+    }
+
+    /// Whether an alert for `key` should be included in the outgoing
+    /// notification, given the configured cooldown.
+    pub fn should_notify(&self, key: &str, now: i64, cooldown_secs: i64) -> bool {
+        match self.last_fired.get(key) {
+            Some(last) => now - last >= cooldown_secs,
+            None => true,
+        }
+    }
+
+    pub fn mark_fired(&mut self, key: &str, now: i64) {
+        self.last_fired.insert(key.to_string(), now);
+    }
+
+    /// Drop the key so the alert can fire immediately the next time it's active.
+    pub fn clear(&mut self, key: &str) {
+        self.last_fired.remove(key);
+    }
+}
+
+pub fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
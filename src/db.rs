@@ -0,0 +1,154 @@
+use crate::state::{s3_get_object, s3_put_object};
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// The most recently recorded row for an instance, used to detect state transitions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceRow {
+    pub state: String,
+    pub summary: String,
+    pub system_summary: String,
+    pub ts: i64,
+}
+
+/// One recorded row in an instance's timeline, as returned by `history`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceHistoryEntry {
+    pub name: String,
+    pub row: InstanceRow,
+}
+
+/// Records every observed instance and queue snapshot with a timestamp, so
+/// `--only_changes` can diff against the last known row per instance instead
+/// of a single flattened paragraph.
+///
+/// The working copy always lives on local disk (sqlite needs a real file),
+/// but on ephemeral/Lambda filesystems that copy doesn't survive between
+/// invocations. When `--state-bucket` is configured, `open` downloads the
+/// last synced copy from S3 first and `sync` uploads it back after writes,
+/// the same durability trick `state.rs` uses for the smaller alert blobs.
+pub struct Db {
+    conn: Connection,
+    path: String,
+    state_bucket: Option<String>,
+    db_key: String,
+}
+
+impl Db {
+    pub async fn open(path: &str, state_bucket: &Option<String>, db_key: &str) -> Result<Self> {
+        if let Some(bucket) = state_bucket {
+            if let Some(bytes) = s3_get_object(bucket, db_key).await? {
+                tokio::fs::write(path, bytes).await?;
+            }
+        }
+
+        let conn = Connection::open(path)?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS instance_history (
+                id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                state TEXT NOT NULL,
+                summary TEXT NOT NULL,
+                system_summary TEXT NOT NULL,
+                ts INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS instance_history_id_ts ON instance_history (id, ts);
+
+            CREATE TABLE IF NOT EXISTS queue_history (
+                pending INTEGER NOT NULL,
+                errors INTEGER NOT NULL,
+                ts INTEGER NOT NULL
+            );",
+        )?;
+
+        Ok(Self {
+            conn,
+            path: path.to_string(),
+            state_bucket: state_bucket.clone(),
+            db_key: db_key.to_string(),
+        })
+    }
+
+    /// Upload the local working copy back to S3, if a state bucket is configured.
+    /// No-op for the local-disk-only deployment, where the working copy is
+    /// already the durable one.
+    pub async fn sync(&self) -> Result<()> {
+        if let Some(bucket) = &self.state_bucket {
+            let bytes = tokio::fs::read(&self.path).await?;
+            s3_put_object(bucket, &self.db_key, &bytes).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn record_instance(
+        &self,
+        id: &str,
+        name: &str,
+        state: &str,
+        summary: &str,
+        system_summary: &str,
+        ts: i64,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO instance_history (id, name, state, summary, system_summary, ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, name, state, summary, system_summary, ts],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn record_queue(&self, pending: i64, errors: i64, ts: i64) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO queue_history (pending, errors, ts) VALUES (?1, ?2, ?3)",
+            params![pending, errors, ts],
+        )?;
+
+        Ok(())
+    }
+
+    /// The last recorded row for `id`, if this instance has been seen before.
+    pub fn last_instance(&self, id: &str) -> Result<Option<InstanceRow>> {
+        self.conn
+            .query_row(
+                "SELECT state, summary, system_summary, ts FROM instance_history
+                 WHERE id = ?1 ORDER BY ts DESC LIMIT 1",
+                params![id],
+                |row| {
+                    Ok(InstanceRow {
+                        state: row.get(0)?,
+                        summary: row.get(1)?,
+                        system_summary: row.get(2)?,
+                        ts: row.get(3)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// The full recorded timeline for `id`, oldest first, for `monitor history`.
+    pub fn history(&self, id: &str) -> Result<Vec<InstanceHistoryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, state, summary, system_summary, ts FROM instance_history
+             WHERE id = ?1 ORDER BY ts ASC",
+        )?;
+
+        let entries = stmt
+            .query_map(params![id], |row| {
+                Ok(InstanceHistoryEntry {
+                    name: row.get(0)?,
+                    row: InstanceRow {
+                        state: row.get(1)?,
+                        summary: row.get(2)?,
+                        system_summary: row.get(3)?,
+                        ts: row.get(4)?,
+                    },
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(entries)
+    }
+}
@@ -1,22 +1,26 @@
-use anyhow::Result;
+mod alerts;
+mod db;
+mod metrics;
+mod notify;
+mod state;
+
+use alerts::AlertHistory;
+use anyhow::{bail, Result};
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::{BehaviorVersion, Region};
-use aws_sdk_ec2::types::{InstanceState, InstanceStatus, InstanceStatusSummary, Tag};
+use aws_sdk_ec2::types::{
+    InstanceState, InstanceStateName, InstanceStatus, InstanceStatusSummary, SummaryStatus, Tag,
+};
 use aws_sdk_ec2::Client;
-use base64::prelude::BASE64_STANDARD;
-use base64::Engine;
+use db::Db;
 use itertools::Itertools;
-use lettre::{message::MultiPart, Message};
+use metrics::Metrics;
+use notify::Notifier;
 use query::portal::LoginPayload;
-use rusoto_ses::Ses;
-use rusoto_ses::{RawMessage, SendRawEmailRequest, SesClient};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::fs::OpenOptions;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-};
 
 #[derive(Debug, StructOpt, Clone)]
 struct Opt {
@@ -31,6 +35,59 @@ struct Opt {
     email: bool,
     #[structopt(short, long)]
     only_changes: bool,
+    /// Minimum number of seconds between repeat notifications for the same alert.
+    #[structopt(long, default_value = "21600")]
+    cooldown: i64,
+    /// S3 bucket to persist the alert cooldown history in, instead of the local
+    /// filesystem. Required for deployments (containers, Lambda) where `/tmp`
+    /// doesn't survive between runs.
+    #[structopt(long)]
+    state_bucket: Option<String>,
+    #[structopt(long, default_value = "monitor-alerts.json")]
+    alert_key: String,
+    /// Notification backends to fan messages out to.
+    #[structopt(long, default_value = "ses", use_delimiter = true)]
+    notifiers: Vec<String>,
+    #[structopt(long, env = "MONITOR_FROM", default_value = "FK <noreply@fieldkit.org>")]
+    from: String,
+    #[structopt(
+        long,
+        env = "MONITOR_TO",
+        default_value = "Jacob Lewallen <jlewalle@gmail.com>"
+    )]
+    to: String,
+    #[structopt(long, env = "SMTP_HOST")]
+    smtp_host: Option<String>,
+    #[structopt(long, env = "SMTP_USER")]
+    smtp_user: Option<String>,
+    #[structopt(long, env = "SMTP_PASSWORD", hide_env_values = true)]
+    smtp_password: Option<String>,
+    #[structopt(long, env = "WEBHOOK_URL")]
+    webhook_url: Option<String>,
+    /// Run forever, polling on `--interval` instead of checking once and exiting.
+    #[structopt(long)]
+    daemon: bool,
+    /// Seconds between polls in `--daemon` mode.
+    #[structopt(long, default_value = "60")]
+    interval: u64,
+    /// Address the `--daemon` mode's `/metrics` and `/healthz` endpoints bind to.
+    #[structopt(long, default_value = "0.0.0.0:9090")]
+    metrics_addr: String,
+    /// SQLite database recording every observed instance and queue snapshot.
+    #[structopt(long, default_value = "/tmp/monitor-history.db")]
+    db_path: String,
+    /// S3 key the history db is synced to/from when `--state-bucket` is set, so
+    /// the timeline survives on deployments where `--db-path` doesn't.
+    #[structopt(long, default_value = "monitor-history.db")]
+    db_key: String,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+enum Command {
+    /// Print the recorded timeline for a single instance.
+    History { instance_id: String },
 }
 
 #[derive(Default)]
@@ -39,19 +96,46 @@ struct Notification {
 }
 
 impl Notification {
-    async fn send(self) -> Result<()> {
+    const SUBJECT: &'static str = "FK Server Status";
+
+    /// Fan the message out to every configured notifier, retrying each one, and
+    /// only fail the run if every delivery path is exhausted.
+    async fn send(self, notifiers: &[Box<dyn Notifier>], metrics: Option<&Metrics>) -> Result<()> {
         if self.messages.is_empty() {
             return Ok(());
         }
 
-        let ses_client = SesClient::new(rusoto_core::Region::UsEast1);
-
-        let from = "FK <noreply@fieldkit.org>";
-        let to = "Jacob Lewallen <jlewalle@gmail.com>";
-        let subject = "FK Server Status";
         let body = self.messages.join("\n");
 
-        send_email_ses(&ses_client, from, to, subject, body).await?;
+        let mut succeeded = Vec::new();
+        let mut failed = Vec::new();
+
+        for notifier in notifiers {
+            match notify::notify_with_retry(notifier.as_ref(), Self::SUBJECT, &body).await {
+                Ok(()) => {
+                    succeeded.push(notifier.name());
+                    if let Some(metrics) = metrics {
+                        metrics.notifications_sent.inc();
+                    }
+                }
+                Err(e) => {
+                    println!("notifier {} permanently failed: {:?}", notifier.name(), e);
+                    failed.push(notifier.name());
+                }
+            }
+        }
+
+        println!(
+            "notification delivery: {} succeeded {:?}, {} failed {:?}",
+            succeeded.len(),
+            succeeded,
+            failed.len(),
+            failed
+        );
+
+        if succeeded.is_empty() {
+            bail!("all notifiers failed to deliver: {:?}", failed);
+        }
 
         Ok(())
     }
@@ -63,59 +147,129 @@ async fn main() -> Result<()> {
 
     let options = Opt::from_args();
 
-    let mut notification = Notification::default();
-
-    match InstanceChecker::default().check(options.clone()).await {
-        Ok(Some(m)) => notification.messages.push(m),
-        Ok(None) => {}
-        Err(e) => println!("{:?}", e),
+    if let Some(Command::History { instance_id }) = &options.command {
+        return print_history(&options.db_path, &options.state_bucket, &options.db_key, instance_id).await;
     }
 
-    match QueueChecker::default().check(&options).await {
-        Ok(m) => notification.messages.extend(m),
-        Err(e) => println!("{:?}", e),
+    let notifiers = notify::build_notifiers(&options).await?;
+
+    if options.daemon {
+        run_daemon(options, notifiers).await
+    } else {
+        run_once(&options, &notifiers, None).await
     }
+}
+
+async fn print_history(
+    db_path: &str,
+    state_bucket: &Option<String>,
+    db_key: &str,
+    instance_id: &str,
+) -> Result<()> {
+    let db = Db::open(db_path, state_bucket, db_key).await?;
 
-    notification.send().await?;
+    for entry in db.history(instance_id)? {
+        println!(
+            "{} {} {:20} {:20} {:20}",
+            entry.row.ts, entry.name, entry.row.state, entry.row.summary, entry.row.system_summary
+        );
+    }
 
     Ok(())
 }
 
-async fn send_email_ses(
-    ses_client: &SesClient,
-    from: &str,
-    to: &str,
-    subject: &str,
-    body: String,
+/// Poll on `--interval` forever, exposing the last poll's results on `/metrics`.
+async fn run_daemon(options: Opt, notifiers: Vec<Box<dyn Notifier>>) -> Result<()> {
+    let metrics = Arc::new(Metrics::new()?);
+
+    let addr = options.metrics_addr.parse()?;
+    let server_metrics = metrics.clone();
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(server_metrics, addr).await {
+            println!("metrics server error: {:?}", e);
+        }
+    });
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(options.interval));
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = run_once(&options, &notifiers, Some(&metrics)).await {
+            println!("{:?}", e);
+        }
+    }
+}
+
+/// Run the checkers once and notify, optionally recording the results to `metrics`.
+async fn run_once(
+    options: &Opt,
+    notifiers: &[Box<dyn Notifier>],
+    metrics: Option<&Metrics>,
 ) -> Result<()> {
-    let email = Message::builder()
-        .from(from.parse()?)
-        .to(to.parse()?)
-        .subject(subject)
-        .multipart(MultiPart::alternative_plain_html(
-            body.clone(),
-            format!("<pre>{}</pre>", &body),
-        ))?;
-
-    let raw_email = email.formatted();
-
-    let ses_request = SendRawEmailRequest {
-        raw_message: RawMessage {
-            data: BASE64_STANDARD.encode(raw_email).into(),
-        },
-        ..Default::default()
-    };
+    let mut notification = Notification::default();
+    let alert_store = state::build_store(&options.state_bucket, &options.alert_key);
+    let mut alert_history = AlertHistory::load(alert_store.as_ref()).await;
+    let db = Db::open(&options.db_path, &options.state_bucket, &options.db_key).await?;
+
+    match InstanceChecker::default()
+        .check(options.clone(), metrics, &db)
+        .await
+    {
+        Ok(Some(m)) => notification.messages.push(m),
+        Ok(None) => {}
+        Err(e) => {
+            println!("{:?}", e);
+            notification
+                .messages
+                .push(format!("ERROR: instance checker failed: {:?}", e));
+        }
+    }
 
-    ses_client.send_raw_email(ses_request).await?;
+    let mut fired_keys = Vec::new();
 
-    Ok(())
+    match QueueChecker::default()
+        .check(options, &mut alert_history, &mut fired_keys, metrics, &db)
+        .await
+    {
+        Ok(m) => notification.messages.extend(m),
+        Err(e) => {
+            println!("{:?}", e);
+            notification
+                .messages
+                .push(format!("ERROR: queue checker failed: {:?}", e));
+        }
+    }
+
+    // Don't mark an alert fired until it's actually delivered: if every
+    // notifier fails, the condition should still fire again next cooldown
+    // instead of being silently suppressed for a run nobody saw.
+    let send_result = notification.send(notifiers, metrics).await;
+
+    if send_result.is_ok() {
+        let now = alerts::now_unix();
+        for key in &fired_keys {
+            alert_history.mark_fired(key, now);
+        }
+    }
+
+    alert_history.save(alert_store.as_ref()).await?;
+    db.sync().await?;
+
+    send_result
 }
 
 #[derive(Default)]
 struct QueueChecker {}
 
 impl QueueChecker {
-    async fn check(&mut self, opts: &Opt) -> Result<Vec<String>> {
+    async fn check(
+        &mut self,
+        opts: &Opt,
+        alert_history: &mut AlertHistory,
+        fired_keys: &mut Vec<String>,
+        metrics: Option<&Metrics>,
+        db: &Db,
+    ) -> Result<Vec<String>> {
         let pc = query::portal::Client::new(&opts.api)?;
         let token = pc.login(LoginPayload::from_env()?).await?;
         let authed = pc.to_authenticated(token)?;
@@ -123,34 +277,143 @@ impl QueueChecker {
 
         println!("{:?}", health);
 
-        let mut messages = Vec::new();
+        let now = alerts::now_unix();
 
-        if health.queue.pending > 500 {
-            messages.push(format!("WARNING: Queue length is {}", health.queue.pending));
+        if let Some(metrics) = metrics {
+            metrics.queue_pending.set(health.queue.pending as i64);
+            metrics.queue_errors.set(health.queue.errors as i64);
         }
 
-        if health.queue.errors > 500 {
-            messages.push(format!(
-                "WARNING: Error queue length is {}",
-                health.queue.errors
-            ));
-        }
+        db.record_queue(health.queue.pending as i64, health.queue.errors as i64, now)?;
+        let mut messages = Vec::new();
+
+        self.check_threshold(
+            alert_history,
+            "queue.pending",
+            health.queue.pending > 500,
+            now,
+            opts.cooldown,
+            &mut messages,
+            fired_keys,
+            format!("WARNING: Queue length is {}", health.queue.pending),
+        );
+
+        self.check_threshold(
+            alert_history,
+            "queue.errors",
+            health.queue.errors > 500,
+            now,
+            opts.cooldown,
+            &mut messages,
+            fired_keys,
+            format!("WARNING: Error queue length is {}", health.queue.errors),
+        );
 
         Ok(messages)
     }
+
+    /// Emit `message` for `key` unless it already fired within the cooldown
+    /// window, clearing the key's history once the underlying condition clears.
+    /// Doesn't mark the key fired itself — `key` is only pushed onto
+    /// `fired_keys` so the caller can defer that until delivery succeeds.
+    #[allow(clippy::too_many_arguments)]
+    fn check_threshold(
+        &self,
+        alert_history: &mut AlertHistory,
+        key: &str,
+        active: bool,
+        now: i64,
+        cooldown: i64,
+        messages: &mut Vec<String>,
+        fired_keys: &mut Vec<String>,
+        message: String,
+    ) {
+        if active {
+            if alert_history.should_notify(key, now, cooldown) {
+                messages.push(message);
+                fired_keys.push(key.to_string());
+            }
+        } else {
+            alert_history.clear(key);
+        }
+    }
+}
+
+/// Describe what changed for an instance since `previous`, naming only the
+/// fields that actually differ (state, summary, system_summary can each
+/// transition independently).
+fn describe_transition(
+    id: &str,
+    name: &str,
+    previous: Option<&db::InstanceRow>,
+    state: &str,
+    summary: &str,
+    system_summary: &str,
+) -> Option<String> {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return Some(format!("instance {} ({}) first seen: {}", id, name, state)),
+    };
+
+    let mut changes = Vec::new();
+
+    if previous.state != state {
+        changes.push(format!("state {}->{}", previous.state, state));
+    }
+    if previous.summary != summary {
+        changes.push(format!("summary {}->{}", previous.summary, summary));
+    }
+    if previous.system_summary != system_summary {
+        changes.push(format!(
+            "system_summary {}->{}",
+            previous.system_summary, system_summary
+        ));
+    }
+
+    if changes.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "instance {} ({}) went {}",
+            id,
+            name,
+            changes.join(", ")
+        ))
+    }
 }
 
 #[derive(Default)]
 struct InstanceChecker {}
 
 impl InstanceChecker {
-    async fn check(&mut self, opts: Opt) -> Result<Option<String>> {
+    async fn check(
+        &mut self,
+        opts: Opt,
+        metrics: Option<&Metrics>,
+        db: &Db,
+    ) -> Result<Option<String>> {
         let Opt {
             region,
             verbose: _,
             api: _,
             email,
             only_changes,
+            cooldown: _,
+            state_bucket: _,
+            alert_key: _,
+            notifiers: _,
+            from: _,
+            to: _,
+            smtp_host: _,
+            smtp_user: _,
+            smtp_password: _,
+            webhook_url: _,
+            daemon: _,
+            interval: _,
+            metrics_addr: _,
+            db_path: _,
+            db_key: _,
+            command: _,
         } = opts;
 
         let region_provider = RegionProviderChain::first_try(region.map(Region::new))
@@ -167,16 +430,25 @@ impl InstanceChecker {
 
         let servers = self.get_server_status(&client, ids).await?;
 
+        if let Some(metrics) = metrics {
+            for server in &servers {
+                metrics
+                    .instance_up
+                    .with_label_values(&[&server.id, server.name()])
+                    .set(server.is_up() as i64);
+            }
+        }
+
         let summaries: Vec<String> = servers
             .iter()
             .map(|server| {
                 format!(
-                    "{} {:20} {:20?} {:20?} {:20?}",
+                    "{} {:20} {:20} {:20} {:20}",
                     server.id,
                     server.name(),
-                    server.state.as_ref().unwrap().name().unwrap(),
-                    server.summary.as_ref().unwrap().status().unwrap(),
-                    server.system_summary.as_ref().unwrap().status().unwrap()
+                    server.state_name(),
+                    server.summary_status(),
+                    server.system_summary_status(),
                 )
             })
             .collect();
@@ -187,33 +459,35 @@ impl InstanceChecker {
 
         let notifying = email && !only_changes;
 
-        let modified = if only_changes {
-            let state_path = "/tmp/monitor-state.txt";
-            let modified = if let Some(previous) = self.read_previous_state(state_path).await {
-                previous != paragraph
-            } else {
-                true
-            };
-
-            if modified {
-                let mut options = OpenOptions::new();
-                let mut file = options
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(state_path)
-                    .await?;
-                file.write_all(paragraph.as_bytes()).await?;
-                file.flush().await?;
+        // Record every observed snapshot, regardless of --only_changes, so
+        // `monitor history` always has a full timeline to report on.
+        let now = alerts::now_unix();
+        let mut transitions = Vec::new();
+
+        for server in &servers {
+            let state = server.state_name();
+            let summary = server.summary_status();
+            let system_summary = server.system_summary_status();
+
+            if only_changes {
+                let previous = db.last_instance(&server.id)?;
+
+                if let Some(message) =
+                    describe_transition(&server.id, server.name(), previous.as_ref(), &state, &summary, &system_summary)
+                {
+                    transitions.push(message);
+                }
             }
 
-            modified && email
-        } else {
-            false
-        };
+            db.record_instance(&server.id, server.name(), &state, &summary, &system_summary, now)?;
+        }
 
-        if notifying || modified {
+        let modified = !transitions.is_empty() && email;
+
+        if notifying {
             Ok(Some(paragraph))
+        } else if modified {
+            Ok(Some(transitions.join("\n")))
         } else {
             Ok(None)
         }
@@ -264,19 +538,6 @@ impl InstanceChecker {
 
         Ok(servers)
     }
-
-    async fn read_previous_state(&self, path: &str) -> Option<String> {
-        if let Ok(mut file) = File::open(path).await {
-            let mut buffer = String::new();
-            if let Ok(_) = file.read_to_string(&mut buffer).await {
-                Some(buffer)
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    }
 }
 
 #[derive(Debug)]
@@ -298,4 +559,34 @@ impl ServerStatus {
 
         "UNAMED"
     }
+
+    /// Whether this instance should be considered healthy for `fk_instance_up`.
+    pub fn is_up(&self) -> bool {
+        let running = matches!(
+            self.state.as_ref().and_then(|s| s.name()),
+            Some(&InstanceStateName::Running)
+        );
+        let summary_ok = matches!(
+            self.summary.as_ref().and_then(|s| s.status()),
+            Some(&SummaryStatus::Ok)
+        );
+        let system_ok = matches!(
+            self.system_summary.as_ref().and_then(|s| s.status()),
+            Some(&SummaryStatus::Ok)
+        );
+
+        running && summary_ok && system_ok
+    }
+
+    pub fn state_name(&self) -> String {
+        format!("{:?}", self.state.as_ref().and_then(|s| s.name()))
+    }
+
+    pub fn summary_status(&self) -> String {
+        format!("{:?}", self.summary.as_ref().and_then(|s| s.status()))
+    }
+
+    pub fn system_summary_status(&self) -> String {
+        format!("{:?}", self.system_summary.as_ref().and_then(|s| s.status()))
+    }
 }
@@ -0,0 +1,73 @@
+use anyhow::Result;
+use axum::extract::State;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{Encoder, IntCounter, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Prometheus gauges/counters describing the last poll, scraped over `/metrics`
+/// so queue depth and instance health become graphable time series instead of
+/// only firing emails.
+pub struct Metrics {
+    registry: Registry,
+    pub queue_pending: IntGauge,
+    pub queue_errors: IntGauge,
+    pub instance_up: IntGaugeVec,
+    pub notifications_sent: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let queue_pending = IntGauge::new("fk_queue_pending", "Pending items in the admin queue")?;
+        let queue_errors = IntGauge::new("fk_queue_errors", "Items in the admin error queue")?;
+        let instance_up = IntGaugeVec::new(
+            Opts::new("fk_instance_up", "Whether an EC2 instance is reporting healthy (1) or not (0)"),
+            &["id", "name"],
+        )?;
+        let notifications_sent = IntCounter::new(
+            "fk_notifications_sent",
+            "Notifications successfully delivered to a notifier",
+        )?;
+
+        registry.register(Box::new(queue_pending.clone()))?;
+        registry.register(Box::new(queue_errors.clone()))?;
+        registry.register(Box::new(instance_up.clone()))?;
+        registry.register(Box::new(notifications_sent.clone()))?;
+
+        Ok(Self {
+            registry,
+            queue_pending,
+            queue_errors,
+            instance_up,
+            notifications_sent,
+        })
+    }
+
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        encoder.encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Serve `/metrics` (Prometheus text format) and `/healthz` until the process exits.
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(|| async { "ok" }))
+        .with_state(metrics);
+
+    let listener = TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> String {
+    metrics.render().unwrap_or_default()
+}
@@ -0,0 +1,210 @@
+use crate::Opt;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use lettre::message::MultiPart;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rusoto_ses::Ses;
+use rusoto_ses::{RawMessage, SendRawEmailRequest, SesClient};
+
+/// Delivers a notification subject/body somewhere. Selected at startup via
+/// `--notifiers` and fanned out to from `main`.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Short identifier for this backend, used in delivery success/failure summaries.
+    fn name(&self) -> &str;
+    async fn notify(&self, subject: &str, body: &str) -> Result<()>;
+}
+
+/// Retry a single notifier delivery with exponential backoff before giving up,
+/// so a transient SES/SMTP/network failure doesn't drop the message outright.
+pub async fn notify_with_retry(notifier: &dyn Notifier, subject: &str, body: &str) -> Result<()> {
+    const ATTEMPTS: u32 = 3;
+    let mut last_err = None;
+
+    for attempt in 0..ATTEMPTS {
+        match notifier.notify(subject, body).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                println!(
+                    "{} delivery attempt {}/{} failed: {:?}",
+                    notifier.name(),
+                    attempt + 1,
+                    ATTEMPTS,
+                    e
+                );
+                last_err = Some(e);
+                if attempt + 1 < ATTEMPTS {
+                    tokio::time::sleep(std::time::Duration::from_secs(2u64.pow(attempt))).await;
+                }
+            }
+        }
+    }
+
+    Err(last_err.unwrap())
+}
+
+fn build_message(from: &str, to: &str, subject: &str, body: &str) -> Result<Message> {
+    Ok(Message::builder()
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(
+            body.to_string(),
+            format!("<pre>{}</pre>", body),
+        ))?)
+}
+
+pub struct SesNotifier {
+    from: String,
+    to: String,
+}
+
+impl SesNotifier {
+    pub fn new(from: String, to: String) -> Self {
+        Self { from, to }
+    }
+}
+
+#[async_trait]
+impl Notifier for SesNotifier {
+    fn name(&self) -> &str {
+        "ses"
+    }
+
+    async fn notify(&self, subject: &str, body: &str) -> Result<()> {
+        let ses_client = SesClient::new(rusoto_core::Region::UsEast1);
+        let email = build_message(&self.from, &self.to, subject, body)?;
+        let raw_email = email.formatted();
+
+        let ses_request = SendRawEmailRequest {
+            raw_message: RawMessage {
+                data: BASE64_STANDARD.encode(raw_email).into(),
+            },
+            ..Default::default()
+        };
+
+        ses_client.send_raw_email(ses_request).await?;
+
+        Ok(())
+    }
+}
+
+pub struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+    to: String,
+}
+
+impl SmtpNotifier {
+    pub async fn new(
+        host: &str,
+        user: &str,
+        password: &str,
+        from: String,
+        to: String,
+    ) -> Result<Self> {
+        let creds = Credentials::new(user.to_string(), password.to_string());
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(host)?
+            .credentials(creds)
+            .build();
+
+        // Fail fast at startup rather than silently dropping mail on the first real send.
+        if !transport.test_connection().await? {
+            bail!("smtp relay {} rejected the test connection", host);
+        }
+
+        Ok(Self { transport, from, to })
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    async fn notify(&self, subject: &str, body: &str) -> Result<()> {
+        let email = build_message(&self.from, &self.to, subject, body)?;
+        self.transport
+            .send(email)
+            .await
+            .context("sending smtp mail")?;
+
+        Ok(())
+    }
+}
+
+pub struct WebhookNotifier {
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn notify(&self, subject: &str, body: &str) -> Result<()> {
+        let client = reqwest::Client::new();
+
+        client
+            .post(&self.url)
+            .json(&serde_json::json!({ "text": format!("{}\n{}", subject, body) }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Build the notifiers selected by `--notifiers`, failing fast if a selected
+/// backend is missing required configuration.
+pub async fn build_notifiers(opts: &Opt) -> Result<Vec<Box<dyn Notifier>>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+
+    for name in &opts.notifiers {
+        match name.as_str() {
+            "ses" => notifiers.push(Box::new(SesNotifier::new(
+                opts.from.clone(),
+                opts.to.clone(),
+            ))),
+            "smtp" => {
+                let host = opts
+                    .smtp_host
+                    .as_deref()
+                    .context("--smtp-host (or SMTP_HOST) is required for the smtp notifier")?;
+                let user = opts
+                    .smtp_user
+                    .as_deref()
+                    .context("--smtp-user (or SMTP_USER) is required for the smtp notifier")?;
+                let password = opts.smtp_password.as_deref().context(
+                    "--smtp-password (or SMTP_PASSWORD) is required for the smtp notifier",
+                )?;
+                notifiers.push(Box::new(
+                    SmtpNotifier::new(host, user, password, opts.from.clone(), opts.to.clone())
+                        .await?,
+                ));
+            }
+            "webhook" => {
+                let url = opts
+                    .webhook_url
+                    .clone()
+                    .context("--webhook-url (or WEBHOOK_URL) is required for the webhook notifier")?;
+                notifiers.push(Box::new(WebhookNotifier::new(url)));
+            }
+            other => bail!("unknown notifier {:?}", other),
+        }
+    }
+
+    Ok(notifiers)
+}
@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::primitives::ByteStream;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OnceCell;
+
+/// Persists a small blob of text (the alert cooldown history) across
+/// invocations of the tool. Implementations
+/// back this with whatever filesystem is actually durable for the deployment.
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    async fn read(&self) -> Result<Option<String>>;
+    async fn write(&self, contents: &str) -> Result<()>;
+}
+
+/// The original behavior: a plain file, suitable for a long-lived host or cron box.
+pub struct LocalFileStore {
+    path: String,
+}
+
+impl LocalFileStore {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl StateStore for LocalFileStore {
+    async fn read(&self) -> Result<Option<String>> {
+        match File::open(&self.path).await {
+            Ok(mut file) => {
+                let mut buffer = String::new();
+                file.read_to_string(&mut buffer).await?;
+                Ok(Some(buffer))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn write(&self, contents: &str) -> Result<()> {
+        let mut options = OpenOptions::new();
+        let mut file = options
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// For ephemeral containers or Lambda, where `/tmp` doesn't survive between
+/// invocations, keep the same blob in a dedicated S3 object instead.
+pub struct S3StateStore {
+    bucket: String,
+    key: String,
+}
+
+impl S3StateStore {
+    pub fn new(bucket: String, key: String) -> Self {
+        Self { bucket, key }
+    }
+}
+
+#[async_trait]
+impl StateStore for S3StateStore {
+    async fn read(&self) -> Result<Option<String>> {
+        match s3_get_object(&self.bucket, &self.key).await? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn write(&self, contents: &str) -> Result<()> {
+        s3_put_object(&self.bucket, &self.key, contents.as_bytes()).await
+    }
+}
+
+static S3_CLIENT: OnceCell<aws_sdk_s3::Client> = OnceCell::const_new();
+
+/// The shared S3 client, built on first use. A daemon tick can read/write the
+/// alert history and the history db several times each; reuse one client
+/// instead of re-resolving credentials and building a new one per call.
+async fn s3_client() -> &'static aws_sdk_s3::Client {
+    S3_CLIENT
+        .get_or_init(|| async {
+            let shared_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+            aws_sdk_s3::Client::new(&shared_config)
+        })
+        .await
+}
+
+/// Fetch an S3 object as raw bytes, or `None` if it doesn't exist yet. Shared
+/// by `S3StateStore` and the SQLite history db (`db.rs`), which isn't text.
+pub async fn s3_get_object(bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    let client = s3_client().await;
+
+    match client.get_object().bucket(bucket).key(key).send().await {
+        Ok(output) => {
+            let bytes = output
+                .body
+                .collect()
+                .await
+                .context("reading s3 object body")?;
+            Ok(Some(bytes.to_vec()))
+        }
+        Err(aws_sdk_s3::error::SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn s3_put_object(bucket: &str, key: &str, contents: &[u8]) -> Result<()> {
+    let client = s3_client().await;
+
+    client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .body(ByteStream::from(contents.to_vec()))
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Choose the state backend based on CLI configuration: S3 when a bucket is
+/// configured, otherwise a local file under `/tmp`.
+pub fn build_store(state_bucket: &Option<String>, key: &str) -> Box<dyn StateStore> {
+    match state_bucket {
+        Some(bucket) => Box::new(S3StateStore::new(bucket.clone(), key.to_string())),
+        None => Box::new(LocalFileStore::new(format!("/tmp/{}", key))),
+    }
+}